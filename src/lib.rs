@@ -0,0 +1,141 @@
+#![recursion_limit = "128"]
+
+extern crate cast;
+extern crate either;
+#[macro_use]
+extern crate error_chain;
+extern crate inflections;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate quote;
+#[macro_use]
+extern crate serde_json;
+extern crate svd_parser as svd;
+extern crate syn;
+
+pub mod errors;
+mod generate;
+mod util;
+
+use quote::Tokens;
+
+use errors::*;
+
+/// Target architecture the generated API should run on.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Target {
+    CortexM,
+    Msp430,
+    RISCV,
+    None,
+}
+
+impl Target {
+    pub fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "cortex-m" => Target::CortexM,
+            "msp430" => Target::Msp430,
+            "riscv" => Target::RISCV,
+            "none" => Target::None,
+            _ => bail!("unknown target {}", s),
+        })
+    }
+}
+
+/// Options controlling a single `generate` call.
+pub struct Config {
+    pub target: Target,
+    pub nightly: bool,
+}
+
+/// The in-memory artifacts produced by `generate`.
+pub struct Generation {
+    /// The generated device crate's `lib.rs`.
+    pub lib_rs: Tokens,
+    /// The generated `device.x` linker script (empty if `target` doesn't use one).
+    pub device_x: String,
+    /// The generated `build.rs` (empty if `target` doesn't use one).
+    pub build_rs: Tokens,
+}
+
+/// Parses `svd_xml` and renders the device API it describes according to `config`.
+pub fn generate(svd_xml: &str, config: &Config) -> Result<Generation> {
+    let device = svd::parse(svd_xml);
+
+    let mut device_x = String::new();
+    let items = generate::device::render(
+        &device,
+        &config.target,
+        config.nightly,
+        &mut device_x,
+    )?;
+
+    let build_rs = match config.target {
+        Target::CortexM | Target::Msp430 | Target::RISCV => build_rs(),
+        Target::None => quote!(),
+    };
+
+    Ok(Generation {
+        lib_rs: quote!(#(#items)*),
+        device_x,
+        build_rs,
+    })
+}
+
+/// Parses `svd_xml` and serializes the resulting `svd::Device` — its
+/// peripherals, registers, fields, enumerated values and interrupt numbers —
+/// to a `serde_json::Value`, without generating any Rust.
+pub fn device_json(svd_xml: &str) -> serde_json::Value {
+    let device = svd::parse(svd_xml);
+
+    json!({
+        "peripherals": device.peripherals.iter().map(|p| json!({
+            "name": p.name,
+            "base_address": p.base_address,
+            "interrupt": p.interrupt.iter().map(|i| json!({
+                "name": i.name,
+                "value": i.value,
+            })).collect::<Vec<_>>(),
+            "registers": p.registers.as_ref().map(|regs| regs.iter().map(|r| json!({
+                "name": r.name,
+                "address_offset": r.address_offset,
+                "fields": r.fields.as_ref().map(|fields| fields.iter().map(|f| json!({
+                    "name": f.name,
+                    "enumerated_values": f.enumerated_values.iter().map(|e| json!({
+                        "name": e.name,
+                        "values": e.values.iter().map(|v| json!({
+                            "name": v.name,
+                            "value": v.value,
+                        })).collect::<Vec<_>>(),
+                    })).collect::<Vec<_>>(),
+                })).collect::<Vec<_>>())
+            })).collect::<Vec<_>>())
+        })).collect::<Vec<_>>(),
+    })
+}
+
+fn build_rs() -> Tokens {
+    quote! {
+        use std::env;
+        use std::fs::File;
+        use std::io::Write;
+        use std::path::PathBuf;
+
+        fn main() {
+            if env::var_os("CARGO_FEATURE_RT").is_some() {
+                // Put the linker script somewhere the linker can find it
+                let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+                File::create(out.join("device.x"))
+                    .unwrap()
+                    .write_all(include_bytes!("device.x"))
+                    .unwrap();
+                println!("cargo:rustc-link-search={}", out.display());
+
+                println!("cargo:rerun-if-changed=device.x");
+            }
+
+            println!("cargo:rerun-if-changed=build.rs");
+        }
+    }
+}
@@ -1,52 +1,74 @@
-#![recursion_limit = "128"]
-
-extern crate cast;
+extern crate backtrace;
 extern crate clap;
-extern crate either;
 extern crate env_logger;
 #[macro_use]
 extern crate error_chain;
-extern crate inflections;
 #[macro_use]
 extern crate log;
 #[macro_use]
-extern crate quote;
-extern crate svd_parser as svd;
-extern crate syn;
-
-mod errors;
-mod generate;
-mod util;
+extern crate serde_json;
+extern crate svd2rust;
 
 use std::fs::File;
-use std::process;
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
 
-use quote::Tokens;
 use clap::{App, Arg};
 
-use errors::*;
+use svd2rust::errors::*;
+use svd2rust::{device_json, generate, Config, Target};
+
+const VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    include_str!(concat!(env!("OUT_DIR"), "/commit-info.txt"))
+);
 
-#[derive(Clone, Copy, PartialEq)]
-pub enum Target {
-    CortexM,
-    Msp430,
-    RISCV,
-    None,
+/// An artifact `run()` can be asked to produce via `--emit`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Emit {
+    Lib,
+    Linker,
+    Build,
+    DeviceJson,
 }
 
-impl Target {
+impl Emit {
     fn parse(s: &str) -> Result<Self> {
         Ok(match s {
-            "cortex-m" => Target::CortexM,
-            "msp430" => Target::Msp430,
-            "riscv" => Target::RISCV,
-            "none" => Target::None,
-            _ => bail!("unknown target {}", s),
+            "lib" => Emit::Lib,
+            "linker" => Emit::Linker,
+            "build" => Emit::Build,
+            "device-json" => Emit::DeviceJson,
+            _ => bail!("unknown emit kind {}", s),
         })
     }
 }
 
+/// Expands `@file` arguments in `args` into the non-empty lines of `file`, leaving
+/// every other argument untouched. This lets projects that regenerate many
+/// chips keep a checked-in `svd2rust.args` file instead of a long shell
+/// invocation.
+fn expand_argfiles<I>(args: I) -> Result<Vec<String>>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut expanded = Vec::new();
+
+    for arg in args {
+        if arg.starts_with('@') {
+            let path = &arg[1..];
+            let contents = std::fs::read_to_string(path)
+                .chain_err(|| format!("couldn't read argfile {}", path))?;
+            expanded.extend(contents.lines().filter(|line| !line.is_empty()).map(String::from));
+        } else {
+            expanded.push(arg);
+        }
+    }
+
+    Ok(expanded)
+}
+
 fn run() -> Result<()> {
     use std::io::Read;
 
@@ -71,6 +93,22 @@ fn run() -> Result<()> {
                 .long("nightly")
                 .help("Enable features only available to nightly rustc")
         )
+        .arg(
+            Arg::with_name("emit")
+                .long("emit")
+                .help("Comma-separated list of artifacts to emit (defaults to lib,linker,build); if device-json is listed, only the device JSON is printed")
+                .takes_value(true)
+                .value_name("KIND[,KIND...]")
+                .possible_values(&["lib", "linker", "build", "device-json"])
+                .use_delimiter(true)
+        )
+        .arg(
+            Arg::with_name("output_dir")
+                .long("output-dir")
+                .help("Directory to place generated artifacts in (defaults to the current directory)")
+                .takes_value(true)
+                .value_name("DIR")
+        )
         .arg(
             Arg::with_name("log_level")
                 .long("log")
@@ -82,11 +120,17 @@ fn run() -> Result<()> {
                 .takes_value(true)
                 .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
         )
-        .version(concat!(
-            env!("CARGO_PKG_VERSION"),
-            include_str!(concat!(env!("OUT_DIR"), "/commit-info.txt"))
-        ))
-        .get_matches();
+        .arg(
+            Arg::with_name("log_format")
+                .long("log-format")
+                .help("Choose the format log messages are printed in")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["human", "json"])
+                .default_value("human")
+        )
+        .version(VERSION)
+        .get_matches_from(expand_argfiles(std::env::args())?);
 
     setup_logging(&matches);
 
@@ -95,6 +139,13 @@ fn run() -> Result<()> {
         .map(|s| Target::parse(s))
         .unwrap_or(Ok(Target::CortexM))?;
 
+    let nightly = matches.is_present("nightly_features");
+
+    let emit_kinds = match matches.values_of("emit") {
+        Some(values) => values.map(Emit::parse).collect::<Result<Vec<_>>>()?,
+        None => vec![Emit::Lib, Emit::Linker, Emit::Build],
+    };
+
     let xml = &mut String::new();
     match matches.value_of("input") {
         Some(file) => {
@@ -112,29 +163,67 @@ fn run() -> Result<()> {
         }
     }
 
-    let device = svd::parse(xml);
+    if emit_kinds.contains(&Emit::DeviceJson) {
+        println!("{}", device_json(xml));
+        return Ok(());
+    }
 
-    let nightly = matches.is_present("nightly_features");
+    let config = Config { target, nightly };
+    let generation = generate(xml, &config)?;
 
-    let mut device_x = String::new();
-    let items = generate::device::render(&device, &target, nightly, &mut device_x)?;
+    let output_dir = matches
+        .value_of("output_dir")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."));
 
-    if target == Target::CortexM {
-        writeln!(File::create("lib.rs").unwrap(), "{}", quote!(#(#items)*)).unwrap();
-        writeln!(File::create("device.x").unwrap(), "{}", device_x).unwrap();
-        writeln!(File::create("build.rs").unwrap(), "{}", build_rs()).unwrap();
+    if target == Target::None {
+        if emit_kinds.contains(&Emit::Lib) {
+            println!("{}", generation.lib_rs);
+        }
     } else {
-        println!(
-            "{}",
-            quote! {
-                #(#items)*
-            }
-        );
+        if emit_kinds.contains(&Emit::Lib) {
+            write_file(&output_dir, "lib.rs", &generation.lib_rs.to_string())?;
+        }
+        if emit_kinds.contains(&Emit::Linker) {
+            write_file(&output_dir, "device.x", &generation.device_x)?;
+        }
+        if emit_kinds.contains(&Emit::Build) {
+            write_file(&output_dir, "build.rs", &generation.build_rs.to_string())?;
+        }
     }
 
     Ok(())
 }
 
+fn write_file(output_dir: &Path, name: &str, contents: &str) -> Result<()> {
+    let path = output_dir.join(name);
+    writeln!(
+        File::create(&path).chain_err(|| format!("couldn't create {}", path.display()))?,
+        "{}",
+        contents
+    ).chain_err(|| format!("couldn't write {}", path.display()))?;
+
+    Ok(())
+}
+
+/// Collects a log record's key-value pairs into a JSON object so
+/// `setup_logging`'s JSON formatter can merge them into the emitted line.
+struct JsonKvVisitor<'a> {
+    fields: &'a mut serde_json::Map<String, serde_json::Value>,
+}
+
+impl<'kvs, 'a> log::kv::Visitor<'kvs> for JsonKvVisitor<'a> {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        val: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        self.fields
+            .insert(key.to_string(), json!(val.to_string()));
+        Ok(())
+    }
+}
+
 fn setup_logging(matches: &clap::ArgMatches) {
     // * Log at info by default.
     // * Allow users the option of setting complex logging filters using
@@ -160,10 +249,64 @@ fn setup_logging(matches: &clap::ArgMatches) {
         builder.filter_level(level);
     }
 
+    if matches.value_of("log_format") == Some("json") {
+        builder.format(|buf, record| {
+            let mut fields = serde_json::Map::new();
+            fields.insert("level".to_string(), json!(record.level().to_string()));
+            fields.insert("target".to_string(), json!(record.target()));
+            fields.insert("message".to_string(), json!(record.args().to_string()));
+
+            // Any key-value pairs a call site attached via log's kv API (e.g.
+            // the peripheral or register name being processed) are merged in
+            // as extra fields rather than being dropped.
+            let mut visitor = JsonKvVisitor { fields: &mut fields };
+            let _ = record.key_values().visit(&mut visitor);
+
+            writeln!(buf, "{}", serde_json::Value::Object(fields))
+        });
+    }
+
     builder.init();
 }
 
+/// Replaces the default panic hook with one that points users at the bug
+/// tracker instead of dumping a raw backtrace, since an internal panic means
+/// svd2rust choked on something in the input SVD rather than the user's fault.
+///
+/// Writes straight to stderr with `eprintln!` rather than the `log` macros so
+/// the banner can't be swallowed by `--log off`/`RUST_LOG=off`, matching the
+/// unconditional behavior of the default Rust panic hook it replaces.
+fn install_ice_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        eprintln!("error: svd2rust hit an internal error, which is always a bug.");
+        eprintln!("note: please file an issue at https://github.com/rust-embedded/svd2rust/issues");
+        eprintln!("note: and include the SVD file that triggered it, if possible.");
+        eprintln!("note: svd2rust {}", VERSION);
+
+        if let Some(location) = info.location() {
+            eprintln!("note: panicked at {}:{}", location.file(), location.line());
+        }
+
+        let message = info
+            .payload()
+            .downcast_ref::<&str>()
+            .map(|s| *s)
+            .or_else(|| info.payload().downcast_ref::<String>().map(|s| s.as_str()));
+        if let Some(message) = message {
+            eprintln!("note: {}", message);
+        }
+
+        if std::env::var_os("RUST_BACKTRACE").is_some() {
+            eprintln!("note: backtrace: {:?}", backtrace::Backtrace::new());
+        } else {
+            eprintln!("note: run with `RUST_BACKTRACE=1` for a backtrace");
+        }
+    }));
+}
+
 fn main() {
+    install_ice_hook();
+
     if let Err(ref e) = run() {
         error!("{}", e);
 
@@ -181,27 +324,52 @@ fn main() {
     }
 }
 
-fn build_rs() -> Tokens {
-    quote! {
-        use std::env;
-        use std::fs::File;
-        use std::io::Write;
-        use std::path::PathBuf;
-
-        fn main() {
-            if env::var_os("CARGO_FEATURE_RT").is_some() {
-                // Put the linker script somewhere the linker can find it
-                let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
-                File::create(out.join("device.x"))
-                    .unwrap()
-                    .write_all(include_bytes!("device.x"))
-                    .unwrap();
-                println!("cargo:rustc-link-search={}", out.display());
-
-                println!("cargo:rerun-if-changed=device.x");
-            }
-
-            println!("cargo:rerun-if-changed=build.rs");
-        }
+#[cfg(test)]
+mod tests {
+    use super::expand_argfiles;
+
+    fn write_argfile(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn expands_an_argfile_into_its_non_empty_lines() {
+        let path = write_argfile(
+            "svd2rust-test-expand-argfiles-happy.args",
+            "--target\nmsp430\n\n--nightly\n",
+        );
+
+        let args = expand_argfiles(vec![
+            "svd2rust".to_string(),
+            format!("@{}", path.display()),
+            "-i".to_string(),
+            "chip.svd".to_string(),
+        ]).unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            args,
+            vec!["svd2rust", "--target", "msp430", "--nightly", "-i", "chip.svd"]
+        );
+    }
+
+    #[test]
+    fn passes_through_plain_arguments_unchanged() {
+        let args =
+            expand_argfiles(vec!["svd2rust".to_string(), "-i".to_string(), "chip.svd".to_string()])
+                .unwrap();
+
+        assert_eq!(args, vec!["svd2rust", "-i", "chip.svd"]);
+    }
+
+    #[test]
+    fn errors_on_a_missing_argfile() {
+        let err =
+            expand_argfiles(vec!["@/no/such/file/svd2rust.args".to_string()]).unwrap_err();
+
+        assert!(err.to_string().contains("/no/such/file/svd2rust.args"));
     }
 }